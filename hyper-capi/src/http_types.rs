@@ -138,4 +138,54 @@ ffi_fn! {
         headers.insert(name, value);
         hyper_error::Ok
     }
+}
+
+ffi_fn! {
+    /// Add a header to the headers map.
+    ///
+    /// Unlike `hyper_headers_set`, this does not overwrite any existing
+    /// values for the header name, so it can be called repeatedly to build
+    /// up a multi-valued header, such as `Set-Cookie`.
+    fn hyper_headers_add(headers: *mut HeaderMap, name: hyper_str, value: hyper_str) -> hyper_error {
+        let headers = unsafe { &mut *headers };
+        let name = match HeaderName::from_bytes(unsafe { name.as_slice() }) {
+            Ok(name) => name,
+            Err(_) => return hyper_error::Kaboom,
+        };
+        let value = match HeaderValue::from_bytes(unsafe { value.as_slice() }) {
+            Ok(val) => val,
+            Err(_) => return hyper_error::Kaboom,
+        };
+
+        headers.append(name, value);
+        hyper_error::Ok
+    }
+}
+
+ffi_fn! {
+    /// Look up a header by name, writing its first value into `val`.
+    ///
+    /// Returns `hyper_error::Kaboom` if `name` isn't a valid header name, or
+    /// if the headers map has no value for it. Use `hyper_headers_iter` to
+    /// see every value of a multi-valued header.
+    fn hyper_headers_get(headers: *const HeaderMap, name: hyper_str, val: *mut hyper_str) -> hyper_error {
+        let headers = unsafe { &*headers };
+        let name = match HeaderName::from_bytes(unsafe { name.as_slice() }) {
+            Ok(name) => name,
+            Err(_) => return hyper_error::Kaboom,
+        };
+
+        match headers.get(&name) {
+            Some(value) => {
+                unsafe {
+                    *val = hyper_str {
+                        buf: value.as_bytes().as_ptr(),
+                        len: value.as_bytes().len(),
+                    };
+                }
+                hyper_error::Ok
+            }
+            None => hyper_error::Kaboom,
+        }
+    }
 }
\ No newline at end of file
@@ -1,8 +1,11 @@
 use std::ffi::c_void;
+use std::io::IoSlice;
+use std::os::unix::io::RawFd;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
 use libc::size_t;
+use tokio::io::unix::AsyncFd;
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use super::task::hyper_context;
@@ -14,13 +17,61 @@ type hyper_io_read_callback =
     extern "C" fn(*mut c_void, *mut hyper_context<'_>, *mut u8, size_t) -> size_t;
 type hyper_io_write_callback =
     extern "C" fn(*mut c_void, *mut hyper_context<'_>, *const u8, size_t) -> size_t;
+type hyper_io_write_vectored_callback = extern "C" fn(
+    *mut c_void,
+    *mut hyper_context<'_>,
+    *const hyper_iovec,
+    size_t,
+) -> size_t;
+type hyper_io_flush_callback = extern "C" fn(*mut c_void, *mut hyper_context<'_>) -> size_t;
+type hyper_io_shutdown_callback = extern "C" fn(*mut c_void, *mut hyper_context<'_>) -> size_t;
 
-pub struct Io {
+/// A borrowed buffer, the `hyper` version of `std::io::IoSlice`.
+///
+/// This is repr(C) to match a POSIX `struct iovec`.
+#[repr(C)]
+pub struct hyper_iovec {
+    buf: *const u8,
+    len: size_t,
+}
+
+/// The callback-driven transport, the original `hyper_io` backend.
+struct CallbackIo {
     read: hyper_io_read_callback,
     write: hyper_io_write_callback,
+    write_vectored: Option<hyper_io_write_vectored_callback>,
+    flush: Option<hyper_io_flush_callback>,
+    shutdown: Option<hyper_io_shutdown_callback>,
+    write_buffer: Option<WriteBuffer>,
     userdata: *mut c_void,
 }
 
+/// An accumulation buffer that coalesces small writes before they're handed
+/// to the underlying write callback.
+struct WriteBuffer {
+    buf: Vec<u8>,
+    capacity: usize,
+}
+
+enum IoInner {
+    Callback(CallbackIo),
+    Fd(AsyncFd<RawFd>),
+}
+
+pub struct Io {
+    inner: IoInner,
+}
+
+impl Drop for Io {
+    fn drop(&mut self) {
+        if let IoInner::Fd(async_fd) = &self.inner {
+            unsafe {
+                libc::close(*async_fd.get_ref());
+            }
+        }
+    }
+}
+
 ffi_fn! {
     /// Create a new IO type used to represent a transport.
     ///
@@ -28,9 +79,53 @@ ffi_fn! {
     /// `hyper_io_set_read` and `hyper_io_set_write`.
     fn hyper_io_new() -> *mut Io {
         Box::into_raw(Box::new(Io {
-            read: read_noop,
-            write: write_noop,
-            userdata: std::ptr::null_mut(),
+            inner: IoInner::Callback(CallbackIo {
+                read: read_noop,
+                write: write_noop,
+                write_vectored: None,
+                flush: None,
+                shutdown: None,
+                write_buffer: None,
+                userdata: std::ptr::null_mut(),
+            }),
+        }))
+    }
+}
+
+ffi_fn! {
+    /// Create a new IO type backed directly by a raw file descriptor.
+    ///
+    /// Unlike `hyper_io_new`, no read or write callbacks need to be
+    /// registered: the descriptor is registered with the Tokio reactor, set
+    /// to non-blocking mode, and `read(2)`/`write(2)` are issued directly
+    /// against it whenever hyper polls this transport. This avoids embedders
+    /// having to hand-write the waker/readiness glue that the callback-based
+    /// `hyper_io` requires for the common case of a plain socket.
+    ///
+    /// This takes ownership of `fd` — it must not be closed elsewhere, and
+    /// it will be closed automatically once the returned `hyper_io` is
+    /// freed or consumed by a handshake.
+    ///
+    /// Returns `NULL` if the descriptor could not be set to non-blocking
+    /// mode or registered with the reactor. This must be called from within
+    /// a Tokio runtime; without a current reactor, registration panics
+    /// instead of returning `NULL`.
+    fn hyper_io_new_from_fd(fd: i32) -> *mut Io {
+        if set_nonblocking(fd).is_err() {
+            unsafe { libc::close(fd) };
+            return std::ptr::null_mut();
+        }
+
+        let async_fd = match AsyncFd::new(fd) {
+            Ok(async_fd) => async_fd,
+            Err(_) => {
+                unsafe { libc::close(fd) };
+                return std::ptr::null_mut();
+            }
+        };
+
+        Box::into_raw(Box::new(Io {
+            inner: IoInner::Fd(async_fd),
         }))
     }
 }
@@ -49,8 +144,12 @@ ffi_fn! {
     /// Set the user data pointer for this IO to some value.
     ///
     /// This value is passed as an argument to the read and write callbacks.
+    ///
+    /// Has no effect on a `hyper_io` created by `hyper_io_new_from_fd`.
     fn hyper_io_set_userdata(io: *mut Io, data: *mut c_void) {
-        unsafe { &mut *io }.userdata = data;
+        if let IoInner::Callback(cb) = &mut unsafe { &mut *io }.inner {
+            cb.userdata = data;
+        }
     }
 }
 
@@ -67,8 +166,12 @@ ffi_fn! {
     ///
     /// If there is an irrecoverable error reading data, then `HYPER_IO_ERROR`
     /// should be the return value.
+    ///
+    /// Has no effect on a `hyper_io` created by `hyper_io_new_from_fd`.
     fn hyper_io_set_read(io: *mut Io, func: hyper_io_read_callback) {
-        unsafe { &mut *io }.read = func;
+        if let IoInner::Callback(cb) = &mut unsafe { &mut *io }.inner {
+            cb.read = func;
+        }
     }
 }
 
@@ -86,8 +189,118 @@ ffi_fn! {
     ///
     /// If there is an irrecoverable error reading data, then `HYPER_IO_ERROR`
     /// should be the return value.
+    ///
+    /// Has no effect on a `hyper_io` created by `hyper_io_new_from_fd`.
     fn hyper_io_set_write(io: *mut Io, func: hyper_io_write_callback) {
-        unsafe { &mut *io }.write = func;
+        if let IoInner::Callback(cb) = &mut unsafe { &mut *io }.inner {
+            cb.write = func;
+        }
+    }
+}
+
+ffi_fn! {
+    /// Set the vectored write function for this IO transport.
+    ///
+    /// Behaves the same as the function set by `hyper_io_set_write`, except
+    /// that it is given an array of `hyper_iovec` buffers to write from,
+    /// instead of a single contiguous one. This allows hyper to hand the
+    /// transport a header block and body chunks in a single call instead of
+    /// issuing a separate write for each.
+    ///
+    /// Registering this callback is optional. If it isn't set, hyper will
+    /// always use the scalar write function instead.
+    ///
+    /// Has no effect on a `hyper_io` created by `hyper_io_new_from_fd`.
+    fn hyper_io_set_write_vectored(io: *mut Io, func: hyper_io_write_vectored_callback) {
+        if let IoInner::Callback(cb) = &mut unsafe { &mut *io }.inner {
+            cb.write_vectored = Some(func);
+        }
+    }
+}
+
+ffi_fn! {
+    /// Set the flush function for this IO transport.
+    ///
+    /// This should ensure that any previously written data has actually been
+    /// sent out the transport, not just buffered internally.
+    ///
+    /// If the flush cannot complete yet, a waker should be claimed from the
+    /// `ctx` and registered with whatever polling mechanism is used to
+    /// signal when it is safe to try again. The return value should be
+    /// `HYPER_IO_PENDING`.
+    ///
+    /// If there is an irrecoverable error flushing data, then
+    /// `HYPER_IO_ERROR` should be the return value.
+    ///
+    /// Registering this callback is optional. If it isn't set, flushing is
+    /// treated as an immediate no-op.
+    ///
+    /// Has no effect on a `hyper_io` created by `hyper_io_new_from_fd`.
+    fn hyper_io_set_flush(io: *mut Io, func: hyper_io_flush_callback) {
+        if let IoInner::Callback(cb) = &mut unsafe { &mut *io }.inner {
+            cb.flush = Some(func);
+        }
+    }
+}
+
+ffi_fn! {
+    /// Set the shutdown function for this IO transport.
+    ///
+    /// This should ensure that any previously written data has actually been
+    /// sent out the transport, and that the transport is then closed.
+    ///
+    /// If the shutdown cannot complete yet, a waker should be claimed from
+    /// the `ctx` and registered with whatever polling mechanism is used to
+    /// signal when it is safe to try again. The return value should be
+    /// `HYPER_IO_PENDING`.
+    ///
+    /// If there is an irrecoverable error shutting down, then
+    /// `HYPER_IO_ERROR` should be the return value.
+    ///
+    /// Registering this callback is optional. If it isn't set, shutdown is
+    /// treated as an immediate no-op.
+    ///
+    /// Has no effect on a `hyper_io` created by `hyper_io_new_from_fd`.
+    fn hyper_io_set_shutdown(io: *mut Io, func: hyper_io_shutdown_callback) {
+        if let IoInner::Callback(cb) = &mut unsafe { &mut *io }.inner {
+            cb.shutdown = Some(func);
+        }
+    }
+}
+
+ffi_fn! {
+    /// Set an internal buffer to accumulate writes into before handing them
+    /// to the write callback.
+    ///
+    /// Small-write-heavy transports (a syscall per write, or a TLS record
+    /// layer underneath) pay a high per-call cost if hyper's header block
+    /// and body chunks are each written separately. With a buffer set,
+    /// `poll_write` copies into it instead of calling the write callback
+    /// directly, only draining the buffer through the callback once it's
+    /// full; `poll_flush` and `poll_shutdown` always drain it first.
+    ///
+    /// A `capacity` of 0 disables buffering, which is also the default.
+    ///
+    /// Calling this again later, after writes may already have been
+    /// accepted into the existing buffer, carries those bytes forward into
+    /// the new buffer instead of dropping them. If they don't fit under the
+    /// new `capacity`, the buffer is allowed to exceed it until it next
+    /// drains.
+    ///
+    /// Has no effect on a `hyper_io` created by `hyper_io_new_from_fd`.
+    fn hyper_io_set_write_buffer(io: *mut Io, capacity: size_t) {
+        if let IoInner::Callback(cb) = &mut unsafe { &mut *io }.inner {
+            let pending = cb.write_buffer.take().map_or_else(Vec::new, |wb| wb.buf);
+
+            cb.write_buffer = if capacity == 0 && pending.is_empty() {
+                None
+            } else {
+                Some(WriteBuffer {
+                    capacity,
+                    buf: pending,
+                })
+            };
+        }
     }
 }
 
@@ -111,22 +324,99 @@ extern "C" fn write_noop(
     0
 }
 
+/// Drains any bytes sitting in `cb`'s write buffer through the write
+/// callback. Leaves partially-drained state in place if the callback
+/// returns `HYPER_IO_PENDING`, so the next call picks up where this left
+/// off.
+fn poll_drain_write_buffer(cb: &mut CallbackIo, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+    let write = cb.write;
+    let userdata = cb.userdata;
+    let write_buffer = match &mut cb.write_buffer {
+        Some(write_buffer) => write_buffer,
+        None => return Poll::Ready(Ok(())),
+    };
+
+    while !write_buffer.buf.is_empty() {
+        let buf_ptr = write_buffer.buf.as_ptr();
+        let buf_len = write_buffer.buf.len();
+
+        match write(userdata, hyper_context::wrap(cx), buf_ptr, buf_len) {
+            HYPER_IO_PENDING => return Poll::Pending,
+            HYPER_IO_ERROR => {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "io error",
+                )))
+            }
+            0 => {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::WriteZero,
+                    "write zero",
+                )))
+            }
+            n => drop(write_buffer.buf.drain(..n)),
+        }
+    }
+
+    Poll::Ready(Ok(()))
+}
+
+fn set_nonblocking(fd: RawFd) -> std::io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL, 0);
+        if flags < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
 impl AsyncRead for Io {
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<std::io::Result<usize>> {
-        let buf_ptr = buf.as_mut_ptr();
-        let buf_len = buf.len();
+        match &mut self.get_mut().inner {
+            IoInner::Callback(cb) => {
+                let buf_ptr = buf.as_mut_ptr();
+                let buf_len = buf.len();
 
-        match (self.read)(self.userdata, hyper_context::wrap(cx), buf_ptr, buf_len) {
-            HYPER_IO_PENDING => Poll::Pending,
-            HYPER_IO_ERROR => Poll::Ready(Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "io error",
-            ))),
-            ok => Poll::Ready(Ok(ok)),
+                match (cb.read)(cb.userdata, hyper_context::wrap(cx), buf_ptr, buf_len) {
+                    HYPER_IO_PENDING => Poll::Pending,
+                    HYPER_IO_ERROR => Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        "io error",
+                    ))),
+                    ok => Poll::Ready(Ok(ok)),
+                }
+            }
+            IoInner::Fd(async_fd) => loop {
+                let mut guard = match async_fd.poll_read_ready(cx) {
+                    Poll::Ready(Ok(guard)) => guard,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                };
+
+                let result = guard.try_io(|inner| {
+                    let ret = unsafe {
+                        libc::read(*inner.get_ref(), buf.as_mut_ptr() as *mut c_void, buf.len())
+                    };
+                    if ret < 0 {
+                        Err(std::io::Error::last_os_error())
+                    } else {
+                        Ok(ret as usize)
+                    }
+                });
+
+                match result {
+                    Ok(result) => return Poll::Ready(result),
+                    Err(_would_block) => continue,
+                }
+            },
         }
     }
 }
@@ -137,25 +427,245 @@ impl AsyncWrite for Io {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<std::io::Result<usize>> {
-        let buf_ptr = buf.as_ptr();
-        let buf_len = buf.len();
+        match &mut self.get_mut().inner {
+            IoInner::Callback(cb) => {
+                if cb.write_buffer.is_some() {
+                    // Drain first if the buffer is already full, so there's
+                    // always somewhere to put at least one more byte.
+                    while cb.write_buffer.as_ref().unwrap().buf.len()
+                        >= cb.write_buffer.as_ref().unwrap().capacity
+                    {
+                        match poll_drain_write_buffer(cb, cx) {
+                            Poll::Ready(Ok(())) => break,
+                            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                            Poll::Pending => return Poll::Pending,
+                        }
+                    }
+
+                    if cb.write_buffer.as_ref().unwrap().capacity == 0 {
+                        // Buffering was disabled while bytes were still
+                        // pending; they've drained now, so write directly.
+                        cb.write_buffer = None;
+                    } else {
+                        let write_buffer = cb.write_buffer.as_mut().unwrap();
+                        let n = (write_buffer.capacity - write_buffer.buf.len()).min(buf.len());
+                        write_buffer.buf.extend_from_slice(&buf[..n]);
+                        return Poll::Ready(Ok(n));
+                    }
+                }
+
+                let buf_ptr = buf.as_ptr();
+                let buf_len = buf.len();
 
-        match (self.write)(self.userdata, hyper_context::wrap(cx), buf_ptr, buf_len) {
+                match (cb.write)(cb.userdata, hyper_context::wrap(cx), buf_ptr, buf_len) {
+                    HYPER_IO_PENDING => Poll::Pending,
+                    HYPER_IO_ERROR => Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        "io error",
+                    ))),
+                    ok => Poll::Ready(Ok(ok)),
+                }
+            }
+            IoInner::Fd(async_fd) => loop {
+                let mut guard = match async_fd.poll_write_ready(cx) {
+                    Poll::Ready(Ok(guard)) => guard,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                };
+
+                let result = guard.try_io(|inner| {
+                    let ret = unsafe {
+                        libc::write(*inner.get_ref(), buf.as_ptr() as *const c_void, buf.len())
+                    };
+                    if ret < 0 {
+                        Err(std::io::Error::last_os_error())
+                    } else {
+                        Ok(ret as usize)
+                    }
+                });
+
+                match result {
+                    Ok(result) => return Poll::Ready(result),
+                    Err(_would_block) => continue,
+                }
+            },
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let cb = match &mut self.get_mut().inner {
+            IoInner::Callback(cb) => cb,
+            // A raw fd has no userspace buffering to flush.
+            IoInner::Fd(_) => return Poll::Ready(Ok(())),
+        };
+
+        if let Poll::Ready(Err(e)) = poll_drain_write_buffer(cb, cx) {
+            return Poll::Ready(Err(e));
+        } else if cb.write_buffer.is_some() && !cb.write_buffer.as_ref().unwrap().buf.is_empty() {
+            return Poll::Pending;
+        }
+
+        let flush = match cb.flush {
+            Some(cb) => cb,
+            None => return Poll::Ready(Ok(())),
+        };
+
+        match flush(cb.userdata, hyper_context::wrap(cx)) {
             HYPER_IO_PENDING => Poll::Pending,
             HYPER_IO_ERROR => Poll::Ready(Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
                 "io error",
             ))),
-            ok => Poll::Ready(Ok(ok)),
+            _ => Poll::Ready(Ok(())),
         }
     }
 
-    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-        Poll::Ready(Ok(()))
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.get_mut().inner {
+            IoInner::Callback(cb) => {
+                if let Poll::Ready(Err(e)) = poll_drain_write_buffer(cb, cx) {
+                    return Poll::Ready(Err(e));
+                } else if cb.write_buffer.is_some()
+                    && !cb.write_buffer.as_ref().unwrap().buf.is_empty()
+                {
+                    return Poll::Pending;
+                }
+
+                let shutdown = match cb.shutdown {
+                    Some(cb) => cb,
+                    None => return Poll::Ready(Ok(())),
+                };
+
+                match shutdown(cb.userdata, hyper_context::wrap(cx)) {
+                    HYPER_IO_PENDING => Poll::Pending,
+                    HYPER_IO_ERROR => Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        "io error",
+                    ))),
+                    _ => Poll::Ready(Ok(())),
+                }
+            }
+            IoInner::Fd(async_fd) => {
+                // Only the write half; the caller may still be reading, e.g.
+                // to get a response after half-closing the request body.
+                let ret = unsafe { libc::shutdown(*async_fd.get_ref(), libc::SHUT_WR) };
+                if ret < 0 {
+                    let err = std::io::Error::last_os_error();
+                    if err.kind() == std::io::ErrorKind::NotConnected
+                        || err.raw_os_error() == Some(libc::ENOTSOCK)
+                    {
+                        // Already shut down, or not a socket at all, e.g. a
+                        // plain pipe fd.
+                        return Poll::Ready(Ok(()));
+                    }
+                    return Poll::Ready(Err(err));
+                }
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        match &mut self.get_mut().inner {
+            IoInner::Callback(cb) => {
+                // Any bytes already sitting in the write buffer were
+                // accepted by `poll_write` before these `bufs`, so they must
+                // reach the transport first to keep the stream in order.
+                if cb.write_buffer.is_some() {
+                    match poll_drain_write_buffer(cb, cx) {
+                        Poll::Ready(Ok(())) => {}
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+
+                let write_vectored = match cb.write_vectored {
+                    Some(cb) => cb,
+                    None => {
+                        let buf = bufs
+                            .iter()
+                            .find(|b| !b.is_empty())
+                            .map_or(&[][..], |b| &**b);
+                        let buf_ptr = buf.as_ptr();
+                        let buf_len = buf.len();
+
+                        return match (cb.write)(
+                            cb.userdata,
+                            hyper_context::wrap(cx),
+                            buf_ptr,
+                            buf_len,
+                        ) {
+                            HYPER_IO_PENDING => Poll::Pending,
+                            HYPER_IO_ERROR => Poll::Ready(Err(std::io::Error::new(
+                                std::io::ErrorKind::Other,
+                                "io error",
+                            ))),
+                            ok => Poll::Ready(Ok(ok)),
+                        };
+                    }
+                };
+
+                let iovecs: Vec<hyper_iovec> = bufs
+                    .iter()
+                    .map(|buf| hyper_iovec {
+                        buf: buf.as_ptr(),
+                        len: buf.len(),
+                    })
+                    .collect();
+
+                match write_vectored(
+                    cb.userdata,
+                    hyper_context::wrap(cx),
+                    iovecs.as_ptr(),
+                    iovecs.len(),
+                ) {
+                    HYPER_IO_PENDING => Poll::Pending,
+                    HYPER_IO_ERROR => Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        "io error",
+                    ))),
+                    ok => Poll::Ready(Ok(ok)),
+                }
+            }
+            IoInner::Fd(async_fd) => loop {
+                let mut guard = match async_fd.poll_write_ready(cx) {
+                    Poll::Ready(Ok(guard)) => guard,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                };
+
+                let result = guard.try_io(|inner| {
+                    let ret = unsafe {
+                        libc::writev(
+                            *inner.get_ref(),
+                            bufs.as_ptr() as *const libc::iovec,
+                            bufs.len() as i32,
+                        )
+                    };
+                    if ret < 0 {
+                        Err(std::io::Error::last_os_error())
+                    } else {
+                        Ok(ret as usize)
+                    }
+                });
+
+                match result {
+                    Ok(result) => return Poll::Ready(result),
+                    Err(_would_block) => continue,
+                }
+            },
+        }
     }
 
-    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-        Poll::Ready(Ok(()))
+    fn is_write_vectored(&self) -> bool {
+        match &self.inner {
+            IoInner::Callback(cb) => cb.write_vectored.is_some(),
+            IoInner::Fd(_) => true,
+        }
     }
 }
 